@@ -9,174 +9,347 @@ use osmformat;
 use osmformat::{PrimitiveGroup, PrimitiveBlock};
 use std;
 use std::slice;
-use std::collections::BTreeMap;
 use std::iter::Chain;
 use std::iter::Map;
-use objects::{OsmObj, Node, Way, Relation, Ref, OsmId, Tags};
+use objects::{OsmObj, Node, Way, Relation, Ref, OsmId, TagsRef, Info, ElemFilter};
 
-pub type OsmObjs<'a> = Chain<Chain<Map<Node, OsmObj, Nodes<'a>, fn(Node) -> OsmObj>, Map<Way, OsmObj, Ways<'a>, fn(Way) -> OsmObj>>, Map<Relation, OsmObj, Relations<'a>, fn(Relation) -> OsmObj>>;
+/// Resolves a block's stringtable into owned `String`s once, so that
+/// decoding every object's tags in the block is a lookup into this
+/// table rather than a fresh UTF-8 decode per key/value.
+pub fn resolve_stringtable(block: &PrimitiveBlock) -> Vec<String> {
+    block.get_stringtable().get_s().iter()
+        .map(|s| String::from_utf8_lossy(s.as_slice()).into_string())
+        .collect()
+}
+
+pub type OsmObjs<'a> = Chain<Chain<Map<Node<'a>, OsmObj<'a>, Nodes<'a>, fn(Node<'a>) -> OsmObj<'a>>, Map<Way<'a>, OsmObj<'a>, Ways<'a>, fn(Way<'a>) -> OsmObj<'a>>>, Map<Relation<'a>, OsmObj<'a>, Relations<'a>, fn(Relation<'a>) -> OsmObj<'a>>>;
 
-pub fn iter<'a>(g: &'a PrimitiveGroup, b: &'a PrimitiveBlock) -> OsmObjs<'a> {
-    nodes(g, b).map(node_into_obj)
-        .chain(ways(g, b).map(way_into_obj))
-        .chain(relations(g, b).map(rel_into_obj))
+pub fn iter<'a>(g: &'a PrimitiveGroup, b: &'a PrimitiveBlock, table: &'a [String], filter: ElemFilter) -> OsmObjs<'a> {
+    nodes(g, b, table, filter).map(node_into_obj)
+        .chain(ways(g, b, table, filter).map(way_into_obj))
+        .chain(relations(g, b, table, filter).map(rel_into_obj))
 }
-fn node_into_obj(n: Node) -> OsmObj { OsmObj::Node(n) }
-fn way_into_obj(w: Way) -> OsmObj { OsmObj::Way(w) }
-fn rel_into_obj(r: Relation) -> OsmObj { OsmObj::Relation(r) }
+fn node_into_obj<'a>(n: Node<'a>) -> OsmObj<'a> { OsmObj::Node(n) }
+fn way_into_obj<'a>(w: Way<'a>) -> OsmObj<'a> { OsmObj::Way(w) }
+fn rel_into_obj<'a>(r: Relation<'a>) -> OsmObj<'a> { OsmObj::Relation(r) }
 
 pub type Nodes<'a> = std::iter::Chain<SimpleNodes<'a>, DenseNodes<'a>>;
 
-pub fn nodes<'a>(g: &'a PrimitiveGroup, b: &'a PrimitiveBlock) -> Nodes<'a> {
-    simple_nodes(g, b).chain(dense_nodes(g, b))
+pub fn nodes<'a>(g: &'a PrimitiveGroup, b: &'a PrimitiveBlock, table: &'a [String], filter: ElemFilter) -> Nodes<'a> {
+    simple_nodes(g, b, table, filter).chain(dense_nodes(g, b, table, filter))
 }
 
-pub fn simple_nodes<'a>(group: &'a PrimitiveGroup, block: &'a PrimitiveBlock)
+pub fn simple_nodes<'a>(group: &'a PrimitiveGroup, block: &'a PrimitiveBlock,
+                         table: &'a [String], filter: ElemFilter)
                         -> SimpleNodes<'a>
 {
-    SimpleNodes { iter: group.get_nodes().iter(), block: block }
+    SimpleNodes { iter: group.get_nodes().iter(), block: block, table: table, filter: filter }
 }
 
 pub struct SimpleNodes<'a> {
     iter: slice::Iter<'a, osmformat::Node>,
     block: &'a PrimitiveBlock,
+    table: &'a [String],
+    filter: ElemFilter,
 }
-impl<'a> Iterator<Node> for SimpleNodes<'a> {
-    fn next(&mut self) -> Option<Node> {
-        self.iter.next().map(|n| Node {
-            id: n.get_id(),
-            lat: make_lat(n.get_lat(), self.block),
-            lon: make_lat(n.get_lon(), self.block),
-            tags: make_tags(n.get_keys(), n.get_vals(), self.block),
-        })
+impl<'a> Iterator<Node<'a>> for SimpleNodes<'a> {
+    fn next(&mut self) -> Option<Node<'a>> {
+        loop {
+            let n = match self.iter.next() {
+                None => return None,
+                Some(n) => n,
+            };
+            let info = make_info(n, self.block, self.table);
+            let visible = info.as_ref().map_or(true, |i| i.visible);
+            if visible || self.filter == ElemFilter::All {
+                return Some(Node {
+                    id: n.get_id(),
+                    lat: make_lat(n.get_lat(), self.block),
+                    lon: make_lon(n.get_lon(), self.block),
+                    tags: make_tags_ref(n.get_keys(), n.get_vals(), self.table),
+                    info: info,
+                    visible: visible,
+                });
+            }
+        }
     }
     fn size_hint(&self) -> (uint, Option<uint>) {
-        self.iter.size_hint()
+        let (_, upper) = self.iter.size_hint();
+        (0, upper)
     }
 }
 
-pub fn dense_nodes<'a>(group: &'a PrimitiveGroup, block: &'a PrimitiveBlock)
+pub fn dense_nodes<'a>(group: &'a PrimitiveGroup, block: &'a PrimitiveBlock,
+                        table: &'a [String], filter: ElemFilter)
                        -> DenseNodes<'a>
 {
     let dense = group.get_dense();
     DenseNodes {
         block: block,
+        table: table,
+        filter: filter,
         dids: dense.get_id().iter(),
         dlats: dense.get_lat().iter(),
         dlons: dense.get_lon().iter(),
         keys_vals: dense.get_keys_vals().iter(),
+        dense_info: if dense.has_denseinfo() {
+            Some(DenseInfoIters::new(dense.get_denseinfo()))
+        } else {
+            None
+        },
         cur_id: 0,
         cur_lat: 0,
         cur_lon: 0,
     }
 }
+
+// `DenseInfo`'s timestamp, changeset, uid and user_sid arrays are
+// delta-encoded the same way as `dids`/`dlats`, so their running
+// accumulators have to be advanced in lockstep with `DenseNodes::next`;
+// `version` is absolute.
+struct DenseInfoIters<'a> {
+    versions: slice::Iter<'a, i32>,
+    timestamps: slice::Iter<'a, i64>,
+    changesets: slice::Iter<'a, i64>,
+    uids: slice::Iter<'a, i32>,
+    user_sids: slice::Iter<'a, i32>,
+    // Not delta-encoded: parallel to `id[]`, simply zipped in as-is.
+    visibles: slice::Iter<'a, bool>,
+    cur_timestamp: i64,
+    cur_changeset: i64,
+    cur_uid: i32,
+    cur_user_sid: i32,
+}
+impl<'a> DenseInfoIters<'a> {
+    fn new(denseinfo: &'a osmformat::DenseInfo) -> DenseInfoIters<'a> {
+        DenseInfoIters {
+            versions: denseinfo.get_version().iter(),
+            timestamps: denseinfo.get_timestamp().iter(),
+            changesets: denseinfo.get_changeset().iter(),
+            uids: denseinfo.get_uid().iter(),
+            user_sids: denseinfo.get_user_sid().iter(),
+            visibles: denseinfo.get_visible().iter(),
+            cur_timestamp: 0,
+            cur_changeset: 0,
+            cur_uid: 0,
+            cur_user_sid: 0,
+        }
+    }
+    fn next(&mut self, block: &PrimitiveBlock, table: &[String]) -> Option<Info> {
+        match (self.versions.next(), self.timestamps.next(), self.changesets.next(),
+               self.uids.next(), self.user_sids.next()) {
+            (Some(&version), Some(&dtimestamp), Some(&dchangeset), Some(&duid), Some(&duser_sid)) => {
+                self.cur_timestamp += dtimestamp;
+                self.cur_changeset += dchangeset;
+                self.cur_uid += duid;
+                self.cur_user_sid += duser_sid;
+                let granularity = block.get_date_granularity() as i64;
+                // `visible` defaults to true when the array is absent
+                // or exhausted, per the regular-extract convention.
+                let visible = default_visible(self.visibles.next().map(|&v| v));
+                Some(Info {
+                    version: version,
+                    timestamp: self.cur_timestamp * granularity,
+                    changeset: self.cur_changeset,
+                    uid: self.cur_uid,
+                    user: table[self.cur_user_sid as uint].clone(),
+                    visible: visible,
+                })
+            }
+            _ => None
+        }
+    }
+}
+
 pub struct DenseNodes<'a> {
     block: &'a PrimitiveBlock,
+    table: &'a [String],
+    filter: ElemFilter,
     dids: slice::Iter<'a, i64>,
     dlats: slice::Iter<'a, i64>,
     dlons: slice::Iter<'a, i64>,
     keys_vals: slice::Iter<'a, i32>,
+    dense_info: Option<DenseInfoIters<'a>>,
     cur_id: i64,
     cur_lat: i64,
     cur_lon: i64,
 }
-impl<'a> Iterator<Node> for DenseNodes<'a> {
-    fn next(&mut self) -> Option<Node> {
-        match (self.dids.next(), self.dlats.next(), self.dlons.next()) {
-            (Some(&did), Some(&dlat), Some(&dlon)) => {
-                self.cur_id += did;
-                self.cur_lat += dlat;
-                self.cur_lon += dlon;
-            }
-            _ => return None
-        }
-        let mut tags = BTreeMap::new();
+impl<'a> Iterator<Node<'a>> for DenseNodes<'a> {
+    fn next(&mut self) -> Option<Node<'a>> {
         loop {
-            let k = match self.keys_vals.next() {
-                None | Some(&0) => break,
-                Some(k) => make_string(*k as uint, self.block),
-            };
-            let v = match self.keys_vals.next() {
-                None => break,
-                Some(v) => make_string(*v as uint, self.block),
-            };
-            tags.insert(k, v);
+            match (self.dids.next(), self.dlats.next(), self.dlons.next()) {
+                (Some(&did), Some(&dlat), Some(&dlon)) => {
+                    self.cur_id += did;
+                    self.cur_lat += dlat;
+                    self.cur_lon += dlon;
+                }
+                _ => return None
+            }
+            let mut kv = Vec::new();
+            loop {
+                let k = match self.keys_vals.next() {
+                    None | Some(&0) => break,
+                    Some(&k) => k as u32,
+                };
+                let v = match self.keys_vals.next() {
+                    None => break,
+                    Some(&v) => v as u32,
+                };
+                kv.push((k, v));
+            }
+            let (block, table) = (self.block, self.table);
+            let info = self.dense_info.as_mut().and_then(|di| di.next(block, table));
+            let visible = info.as_ref().map_or(true, |i| i.visible);
+            if visible || self.filter == ElemFilter::All {
+                return Some(Node {
+                    id: self.cur_id,
+                    lat: make_lat(self.cur_lat, self.block),
+                    lon: make_lon(self.cur_lon, self.block),
+                    tags: TagsRef { kv: kv, table: self.table },
+                    info: info,
+                    visible: visible,
+                });
+            }
         }
-        Some(Node {
-            id: self.cur_id,
-            lat: make_lat(self.cur_lat, self.block),
-            lon: make_lon(self.cur_lon, self.block),
-            tags: tags,
-        })
     }
 }
 
-pub fn ways<'a>(group: &'a PrimitiveGroup, block: &'a PrimitiveBlock) -> Ways<'a> {
-    Ways { iter: group.get_ways().iter(), block: block }
+pub fn ways<'a>(group: &'a PrimitiveGroup, block: &'a PrimitiveBlock,
+                 table: &'a [String], filter: ElemFilter) -> Ways<'a> {
+    Ways { iter: group.get_ways().iter(), block: block, table: table, filter: filter }
 }
 pub struct Ways<'a> {
     iter: slice::Iter<'a, osmformat::Way>,
     block: &'a PrimitiveBlock,
+    table: &'a [String],
+    filter: ElemFilter,
 }
-impl<'a> Iterator<Way> for Ways<'a> {
-    fn next(&mut self) -> Option<Way> {
-        self.iter.next().map(|w| {
-            let mut n = 0;
-            let nodes = w.get_refs().iter().map(|&dn| { n += dn; n }).collect();
-            Way {
-                id: w.get_id(),
-                nodes: nodes,
-                tags: make_tags(w.get_keys(), w.get_vals(), self.block),
+impl<'a> Iterator<Way<'a>> for Ways<'a> {
+    fn next(&mut self) -> Option<Way<'a>> {
+        loop {
+            let w = match self.iter.next() {
+                None => return None,
+                Some(w) => w,
+            };
+            let info = make_info(w, self.block, self.table);
+            let visible = info.as_ref().map_or(true, |i| i.visible);
+            if visible || self.filter == ElemFilter::All {
+                let mut n = 0;
+                let nodes = w.get_refs().iter().map(|&dn| { n += dn; n }).collect();
+                return Some(Way {
+                    id: w.get_id(),
+                    nodes: nodes,
+                    tags: make_tags_ref(w.get_keys(), w.get_vals(), self.table),
+                    info: info,
+                    visible: visible,
+                });
             }
-        })
+        }
     }
     fn size_hint(&self) -> (uint, Option<uint>) {
-        self.iter.size_hint()
+        let (_, upper) = self.iter.size_hint();
+        (0, upper)
     }
 }
 
-pub fn relations<'a>(group: &'a PrimitiveGroup, block: &'a PrimitiveBlock) -> Relations<'a> {
-    Relations { iter: group.get_relations().iter(), block: block }
+pub fn relations<'a>(group: &'a PrimitiveGroup, block: &'a PrimitiveBlock,
+                      table: &'a [String], filter: ElemFilter) -> Relations<'a> {
+    Relations { iter: group.get_relations().iter(), block: block, table: table, filter: filter }
 }
 pub struct Relations<'a> {
     iter: slice::Iter<'a, osmformat::Relation>,
     block: &'a PrimitiveBlock,
+    table: &'a [String],
+    filter: ElemFilter,
 }
-impl<'a> Iterator<Relation> for Relations<'a> {
-    fn next(&mut self) -> Option<Relation> {
+impl<'a> Iterator<Relation<'a>> for Relations<'a> {
+    fn next(&mut self) -> Option<Relation<'a>> {
         use osmformat::Relation_MemberType::*;
-        self.iter.next().map(|rel| {
-            let mut m = 0;
-            let refs = rel.get_memids().iter()
-                .zip(rel.get_types().iter())
-                .zip(rel.get_roles_sid().iter())
-                .map(|((&dm, &t), &role)| {
-                    m += dm;
-                    Ref {
-                        member: match t {
-                            NODE => OsmId::Node(m),
-                            WAY => OsmId::Way(m),
-                            RELATION => OsmId::Relation(m),
-                        },
-                        role: make_string(role as uint, self.block),
-                    }
-                }).collect();
-            Relation {
-                id: rel.get_id(),
-                refs: refs,
-                tags: make_tags(rel.get_keys(), rel.get_vals(), self.block),
+        loop {
+            let rel = match self.iter.next() {
+                None => return None,
+                Some(rel) => rel,
+            };
+            let info = make_info(rel, self.block, self.table);
+            let visible = info.as_ref().map_or(true, |i| i.visible);
+            if visible || self.filter == ElemFilter::All {
+                let mut m = 0;
+                let refs = rel.get_memids().iter()
+                    .zip(rel.get_types().iter())
+                    .zip(rel.get_roles_sid().iter())
+                    .map(|((&dm, &t), &role)| {
+                        m += dm;
+                        Ref {
+                            member: match t {
+                                NODE => OsmId::Node(m),
+                                WAY => OsmId::Way(m),
+                                RELATION => OsmId::Relation(m),
+                            },
+                            role: self.table[role as uint].clone(),
+                        }
+                    }).collect();
+                return Some(Relation {
+                    id: rel.get_id(),
+                    refs: refs,
+                    tags: make_tags_ref(rel.get_keys(), rel.get_vals(), self.table),
+                    info: info,
+                    visible: visible,
+                });
             }
-        })
+        }
     }
     fn size_hint(&self) -> (uint, Option<uint>) {
-        self.iter.size_hint()
+        let (_, upper) = self.iter.size_hint();
+        (0, upper)
+    }
+}
+
+trait HasInfo {
+    fn has_info(&self) -> bool;
+    fn get_info(&self) -> &osmformat::Info;
+}
+impl HasInfo for osmformat::Node {
+    fn has_info(&self) -> bool { self.has_info() }
+    fn get_info(&self) -> &osmformat::Info { self.get_info() }
+}
+impl HasInfo for osmformat::Way {
+    fn has_info(&self) -> bool { self.has_info() }
+    fn get_info(&self) -> &osmformat::Info { self.get_info() }
+}
+impl HasInfo for osmformat::Relation {
+    fn has_info(&self) -> bool { self.has_info() }
+    fn get_info(&self) -> &osmformat::Info { self.get_info() }
+}
+fn make_info<T: HasInfo>(obj: &T, block: &PrimitiveBlock, table: &[String]) -> Option<Info> {
+    if !obj.has_info() {
+        return None;
     }
+    let info = obj.get_info();
+    let granularity = block.get_date_granularity() as i64;
+    Some(Info {
+        version: info.get_version(),
+        timestamp: info.get_timestamp() * granularity,
+        changeset: info.get_changeset(),
+        uid: info.get_uid(),
+        user: table[info.get_user_sid() as uint].clone(),
+        visible: default_visible(if info.has_visible() { Some(info.get_visible()) } else { None }),
+    })
 }
 
-fn make_string(k: uint, block: &osmformat::PrimitiveBlock) -> String {
-    String::from_utf8_lossy(block.get_stringtable().get_s()[k].as_slice())
-        .into_string()
+/// `Info.visible` / `DenseInfo.visible[]` have no proto default, so a
+/// missing value (the normal case for plain, non-history extracts)
+/// must be treated as visible rather than as `false`.
+fn default_visible(v: Option<bool>) -> bool {
+    v.unwrap_or(true)
 }
+
+fn make_tags_ref<'a>(keys: &[u32], vals: &[u32], table: &'a [String]) -> TagsRef<'a> {
+    TagsRef {
+        kv: keys.iter().zip(vals.iter()).map(|(&k, &v)| (k, v)).collect(),
+        table: table,
+    }
+}
+
 fn make_lat(c: i64, b: &osmformat::PrimitiveBlock) -> f64 {
     let granularity = b.get_granularity() as i64;
     1e-9 * (b.get_lat_offset() + granularity * c) as f64
@@ -185,12 +358,36 @@ fn make_lon(c: i64, b: &osmformat::PrimitiveBlock) -> f64 {
     let granularity = b.get_granularity() as i64;
     1e-9 * (b.get_lon_offset() + granularity * c) as f64
 }
-fn make_tags(keys: &[u32], vals: &[u32], b: &PrimitiveBlock) -> Tags {
-    let mut tags = BTreeMap::new();
-    for (&k, &v) in keys.iter().zip(vals.iter()) {
-        let k = make_string(k as uint, b);
-        let v = make_string(v as uint, b);
-        tags.insert(k, v);
+
+#[cfg(test)]
+mod tests {
+    use super::{default_visible, make_tags_ref};
+
+    #[test]
+    fn default_visible_defaults_to_true_when_absent() {
+        assert_eq!(default_visible(None), true);
+    }
+
+    #[test]
+    fn default_visible_keeps_an_explicit_value() {
+        assert_eq!(default_visible(Some(false)), false);
+        assert_eq!(default_visible(Some(true)), true);
+    }
+
+    #[test]
+    fn make_tags_ref_zips_keys_and_values_by_index() {
+        let table = vec!["highway".to_string(), "residential".to_string()];
+        let tags = make_tags_ref(&[0], &[1], table.as_slice());
+        assert_eq!(tags.get("highway"), Some("residential"));
+        assert_eq!(tags.get("missing"), None);
+    }
+
+    #[test]
+    fn make_tags_ref_iter_yields_resolved_pairs() {
+        let table = vec!["highway".to_string(), "residential".to_string(),
+                          "name".to_string(), "Main St".to_string()];
+        let tags = make_tags_ref(&[0, 2], &[1, 3], table.as_slice());
+        let pairs: Vec<(&str, &str)> = tags.iter().collect();
+        assert_eq!(pairs, vec![("highway", "residential"), ("name", "Main St")]);
     }
-    tags
 }
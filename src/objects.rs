@@ -0,0 +1,138 @@
+// Copyright (c) 2014 Guillaume Pinot <texitoi(a)texitoi.eu>
+//
+// This work is free. You can redistribute it and/or modify it under
+// the terms of the Do What The Fuck You Want To Public License,
+// Version 2, as published by Sam Hocevar. See the COPYING file for
+// more details.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::slice;
+
+pub type Tags = BTreeMap<String, String>;
+
+/// Borrowed view of an object's tags: a list of stringtable index
+/// pairs plus the resolved table they index into. Resolving a block's
+/// stringtable happens once per block (see `groups::resolve_stringtable`),
+/// so building this costs no per-tag string allocation, unlike `Tags`.
+#[deriving(Clone)]
+pub struct TagsRef<'a> {
+    pub kv: Vec<(u32, u32)>,
+    pub table: &'a [String],
+}
+impl<'a> TagsRef<'a> {
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.kv.iter()
+            .find(|&&(k, _)| self.table[k as uint].as_slice() == key)
+            .map(|&(_, v)| self.table[v as uint].as_slice())
+    }
+    pub fn iter<'b>(&'b self) -> TagsRefIter<'a, 'b> {
+        TagsRefIter { kv: self.kv.iter(), table: self.table }
+    }
+    /// Resolve into the owned `Tags` form, for callers who need a
+    /// `'static` object outliving the block's stringtable.
+    pub fn to_tags(&self) -> Tags {
+        let mut tags = BTreeMap::new();
+        for &(k, v) in self.kv.iter() {
+            tags.insert(self.table[k as uint].clone(), self.table[v as uint].clone());
+        }
+        tags
+    }
+}
+// `TagsRef` doesn't derive `PartialEq`/`Show` (the underlying `kv`
+// order and `table` reference are an implementation detail), so these
+// compare/print the resolved tag set instead, like `Tags` would.
+impl<'a> PartialEq for TagsRef<'a> {
+    fn eq(&self, other: &TagsRef<'a>) -> bool {
+        self.to_tags() == other.to_tags()
+    }
+}
+impl<'a> fmt::Show for TagsRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.to_tags().fmt(f)
+    }
+}
+// `kv` borrows from the `TagsRef` itself (lifetime `'b`), while the
+// resolved `&str`s it yields borrow from the block's stringtable
+// (lifetime `'a`) — the two are independent, so this needs both.
+pub struct TagsRefIter<'a, 'b> {
+    kv: slice::Iter<'b, (u32, u32)>,
+    table: &'a [String],
+}
+impl<'a, 'b> Iterator<(&'a str, &'a str)> for TagsRefIter<'a, 'b> {
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        self.kv.next().map(|&(k, v)| {
+            (self.table[k as uint].as_slice(), self.table[v as uint].as_slice())
+        })
+    }
+}
+
+/// Edit metadata carried alongside an OSM object, decoded from the
+/// protobuf `Info`/`DenseInfo` messages. Absent when the block does
+/// not carry metadata for the object.
+#[deriving(Clone, Show, PartialEq)]
+pub struct Info {
+    pub version: i32,
+    pub timestamp: i64,
+    pub changeset: i64,
+    pub uid: i32,
+    pub user: String,
+    pub visible: bool,
+}
+
+/// Controls whether group iterators yield deleted/invisible history
+/// entries (as found in `.osh.pbf` history and changeset extracts) or
+/// only the live ones, as regular `.osm.pbf` extracts always are.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum ElemFilter {
+    All,
+    VisibleOnly,
+}
+
+#[deriving(Clone, Show, PartialEq)]
+pub enum OsmId {
+    Node(i64),
+    Way(i64),
+    Relation(i64),
+}
+
+#[deriving(Clone, Show, PartialEq)]
+pub struct Ref {
+    pub member: OsmId,
+    pub role: String,
+}
+
+#[deriving(Clone, Show, PartialEq)]
+pub struct Node<'a> {
+    pub id: i64,
+    pub tags: TagsRef<'a>,
+    pub lat: f64,
+    pub lon: f64,
+    pub info: Option<Info>,
+    pub visible: bool,
+}
+
+#[deriving(Clone, Show, PartialEq)]
+pub struct Way<'a> {
+    pub id: i64,
+    pub tags: TagsRef<'a>,
+    pub nodes: Vec<i64>,
+    pub info: Option<Info>,
+    pub visible: bool,
+}
+
+#[deriving(Clone, Show, PartialEq)]
+pub struct Relation<'a> {
+    pub id: i64,
+    pub tags: TagsRef<'a>,
+    pub refs: Vec<Ref>,
+    pub info: Option<Info>,
+    pub visible: bool,
+}
+
+#[deriving(Clone, Show, PartialEq)]
+pub enum OsmObj<'a> {
+    Node(Node<'a>),
+    Way(Way<'a>),
+    Relation(Relation<'a>),
+}